@@ -0,0 +1,213 @@
+//! Correlation bookkeeping behind [`MqttClient::request`](super::MqttClient::request).
+//!
+//! Each call to `request()` registers a oneshot sender under a unique
+//! correlation id and a deadline. A background reaper thread drops entries
+//! past their deadline, which completes the matching `Receiver` with an
+//! error the same way `cancel_all` does on disconnect — both are just
+//! "stop waiting", so they share one mechanism. `connection`/`mqttstate`
+//! would complete a waiter early by calling `complete()` once an inbound
+//! reply with a matching id arrives; that demultiplexing is a follow-up to
+//! this change, not implemented here.
+
+use mqtt311::Publish;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+
+const REAPER_INTERVAL: Duration = Duration::from_millis(500);
+
+pub(crate) struct PendingRequests {
+    client_id: String,
+    counter: AtomicU64,
+    pending: Mutex<HashMap<String, (oneshot::Sender<Publish>, Instant)>>,
+}
+
+impl PendingRequests {
+    /// Builds the shared table and starts its reaper thread. The thread
+    /// exits once the last `Arc` (held by the owning `MqttClient` and its
+    /// clones) is dropped.
+    pub(crate) fn new(client_id: String) -> Arc<Self> {
+        let this = Arc::new(PendingRequests {
+            client_id,
+            counter: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        });
+
+        let reaper = Arc::downgrade(&this);
+        thread::spawn(move || loop {
+            thread::sleep(REAPER_INTERVAL);
+            match reaper.upgrade() {
+                Some(pending_requests) => pending_requests.reap_expired(),
+                None => return,
+            }
+        });
+
+        this
+    }
+
+    pub(crate) fn next_correlation_id(&self) -> String {
+        let n = self.counter.fetch_add(1, Ordering::SeqCst);
+        format!("{}-{}", self.client_id, n)
+    }
+
+    /// Registers interest in a reply for `correlation_id`, returning the
+    /// receiving half of the oneshot that resolves when it arrives. If
+    /// nothing arrives within `timeout`, the sender is dropped and the
+    /// receiver resolves to `Err(Canceled)` instead.
+    pub(crate) fn register(&self, correlation_id: String, timeout: Duration) -> oneshot::Receiver<Publish> {
+        let (tx, rx) = oneshot::channel();
+        let deadline = Instant::now() + timeout;
+        self.pending.lock().unwrap().insert(correlation_id, (tx, deadline));
+        rx
+    }
+
+    /// Called by `connection`/`mqttstate` when an inbound `Publish` carries a
+    /// correlation id matching a pending `request()` call. Returns `true` if
+    /// a waiter was found and completed.
+    pub(crate) fn complete(&self, correlation_id: &str, reply: Publish) -> bool {
+        match self.pending.lock().unwrap().remove(correlation_id) {
+            Some((tx, _deadline)) => {
+                let _ = tx.send(reply);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops any waiter whose deadline has passed.
+    fn reap_expired(&self) {
+        let now = Instant::now();
+        self.pending.lock().unwrap().retain(|_, (_, deadline)| *deadline > now);
+    }
+
+    /// Drops all pending waiters, e.g. on disconnect, so their futures
+    /// resolve to a cancellation error instead of hanging forever.
+    pub(crate) fn cancel_all(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    #[cfg(test)]
+    fn pending_count(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+/// v3.1.1 has no response-topic/correlation-data properties, so under that
+/// protocol `request()` prepends this small length-prefixed envelope to the
+/// payload instead of encoding anything in the topic name (brokers route on
+/// the literal topic string, so mutating it would send the publish
+/// somewhere no subscriber is listening): `[u16 response_topic_len]
+/// [response_topic][u16 correlation_id_len][correlation_id][payload]`.
+pub(crate) fn encode_v4_envelope(response_topic: &str, correlation_id: &str, payload: Vec<u8>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(2 + response_topic.len() + 2 + correlation_id.len() + payload.len());
+    buf.extend_from_slice(&(response_topic.len() as u16).to_be_bytes());
+    buf.extend_from_slice(response_topic.as_bytes());
+    buf.extend_from_slice(&(correlation_id.len() as u16).to_be_bytes());
+    buf.extend_from_slice(correlation_id.as_bytes());
+    buf.extend_from_slice(&payload);
+    buf
+}
+
+/// Inverse of [`encode_v4_envelope`]; a responder app uses this to recover
+/// the reply topic, correlation id, and original payload from an inbound
+/// v3.1.1 `Publish` built by `MqttClient::request`.
+pub fn decode_v4_envelope(payload: &[u8]) -> Option<(String, String, &[u8])> {
+    let (response_topic, rest) = read_length_prefixed(payload)?;
+    let (correlation_id, payload) = read_length_prefixed(rest)?;
+    Some((response_topic, correlation_id, payload))
+}
+
+fn read_length_prefixed(buf: &[u8]) -> Option<(String, &[u8])> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let (len_bytes, rest) = buf.split_at(2);
+    let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (field_bytes, rest) = rest.split_at(len);
+    let field = String::from_utf8(field_bytes.to_vec()).ok()?;
+    Some((field, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::Future;
+    use std::sync::Arc as StdArc;
+
+    fn publish(payload: &[u8]) -> Publish {
+        Publish {
+            dup: false,
+            qos: mqtt311::QoS::AtLeastOnce,
+            retain: false,
+            topic_name: "whatever".to_owned(),
+            pkid: None,
+            payload: StdArc::new(payload.to_vec()),
+        }
+    }
+
+    #[test]
+    fn register_then_complete_resolves_the_receiver() {
+        let pending = PendingRequests::new("client".to_owned());
+        let rx = pending.register("abc".to_owned(), Duration::from_secs(10));
+
+        assert!(pending.complete("abc", publish(b"reply")));
+        let reply = rx.wait().unwrap();
+        assert_eq!(&**reply.payload, b"reply");
+    }
+
+    #[test]
+    fn complete_on_unknown_id_is_a_noop() {
+        let pending = PendingRequests::new("client".to_owned());
+        assert!(!pending.complete("missing", publish(b"reply")));
+    }
+
+    #[test]
+    fn cancel_all_drops_every_waiter() {
+        let pending = PendingRequests::new("client".to_owned());
+        let rx = pending.register("abc".to_owned(), Duration::from_secs(10));
+        assert_eq!(pending.pending_count(), 1);
+
+        pending.cancel_all();
+        assert_eq!(pending.pending_count(), 0);
+        assert!(rx.wait().is_err());
+    }
+
+    #[test]
+    fn reap_expired_drops_only_past_deadline_entries() {
+        let pending = PendingRequests::new("client".to_owned());
+        let expired = pending.register("expired".to_owned(), Duration::from_millis(0));
+        let fresh = pending.register("fresh".to_owned(), Duration::from_secs(10));
+
+        std::thread::sleep(Duration::from_millis(10));
+        pending.reap_expired();
+
+        assert_eq!(pending.pending_count(), 1);
+        assert!(expired.wait().is_err());
+        assert!(pending.complete("fresh", publish(b"reply")));
+        assert_eq!(&**fresh.wait().unwrap().payload, b"reply");
+    }
+
+    #[test]
+    fn next_correlation_id_is_unique_per_call() {
+        let pending = PendingRequests::new("client".to_owned());
+        let a = pending.next_correlation_id();
+        let b = pending.next_correlation_id();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn v4_envelope_roundtrips() {
+        let encoded = encode_v4_envelope("client/_reply", "client-0", b"hello".to_vec());
+        let (response_topic, correlation_id, payload) = decode_v4_envelope(&encoded).unwrap();
+        assert_eq!(response_topic, "client/_reply");
+        assert_eq!(correlation_id, "client-0");
+        assert_eq!(payload, b"hello");
+    }
+}
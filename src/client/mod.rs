@@ -1,31 +1,114 @@
 use crate::error::{ClientError, ConnectError};
 use crate::MqttOptions;
 use crossbeam_channel;
-use futures::{sync::mpsc, Future, Sink};
+use futures::task::{self, Task};
+use futures::{sync::mpsc, Async, Future, Poll, Sink};
 use mqtt311::{PacketIdentifier, Publish, QoS, Subscribe, Unsubscribe, SubscribeTopic};
-use std::sync::Arc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
 
 pub mod connection;
+pub mod correlation;
 pub mod mqttstate;
 pub mod network;
 pub mod prepend;
+pub mod v5;
 
+use correlation::PendingRequests;
+use v5::{Protocol, PubAckReason, PublishProperties, SubAckReason, SubscribeProperties};
+
+/// `Connected`/`Disconnected`/`Reconnecting` describe the lifecycle events
+/// `connection` already tracks internally via `Request::Reconnect`/
+/// `Disconnect`; wiring `connection` to actually push them onto
+/// `notification_rx` is a follow-up and not part of this change. The one
+/// piece of that wiring that doesn't depend on `connection`'s internals —
+/// turning a v5 DISCONNECT reason code into the generic `DisconnectReason`
+/// a `Disconnected` notification carries — is `From<v5::DisconnectReason>`,
+/// below.
 #[derive(Debug)]
 pub enum Notification {
     Publish(Publish),
+    /// v5-only: an inbound PUBLISH carrying variable-header properties
+    /// (e.g. `response_topic`/`correlation_data`, needed to demultiplex
+    /// `MqttClient::request` replies on the responder side).
+    PublishV5(Publish, PublishProperties),
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
     PubRel(PacketIdentifier),
     PubComp(PacketIdentifier),
-    SubAck(PacketIdentifier),
+    /// Per-topic results of a SUBSCRIBE, aligned with the topics of the
+    /// SUBSCRIBE that triggered it.
+    SubAck(PacketIdentifier, Vec<SubscribeResult>),
+    /// v5-only: a PUBACK carrying a broker reason code.
+    PubAckReasonCode(PacketIdentifier, PubAckReason),
+    /// v5-only: the broker sent a DISCONNECT with this reason code.
+    DisconnectReasonCode(v5::DisconnectReason),
+    /// The event loop established a session with the broker.
+    Connected { session_present: bool },
+    /// The event loop tore down the connection.
+    Disconnected { reason: DisconnectReason },
+    /// The event loop is attempting to re-establish a dropped connection.
+    Reconnecting { attempt: u32 },
     None,
 }
 
+/// Per-topic outcome of a SUBSCRIBE, in the order the topics were
+/// requested, carried by [`Notification::SubAck`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubscribeResult {
+    /// The broker granted the subscription, possibly at a lower QoS.
+    Success(QoS),
+    /// The broker rejected the subscription (v3.1.1 SUBACK code 0x80).
+    Failure,
+    /// v5-only: the broker rejected the subscription with this specific
+    /// failure reason. Grants are never carried here, even on v5 — see
+    /// [`SubAckReason`].
+    ReasonCode(SubAckReason),
+}
+
+/// Why the event loop tore down the connection, carried by
+/// [`Notification::Disconnected`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The application called `MqttClient::disconnect`.
+    Requested,
+    /// The TCP/TLS connection was lost.
+    ConnectionLost,
+    /// The broker didn't respond to a PINGREQ in time.
+    KeepAliveTimeout,
+    /// The broker sent a DISCONNECT (v5 only) with this reason code.
+    Protocol(v5::DisconnectReason),
+    /// An inbound packet's remaining-length exceeded `max_incoming_packet_size`
+    /// and the decoder aborted the read before buffering the body.
+    IncomingPacketTooLarge { limit: usize, actual: usize },
+}
+
+impl From<v5::DisconnectReason> for DisconnectReason {
+    fn from(reason: v5::DisconnectReason) -> Self {
+        DisconnectReason::Protocol(reason)
+    }
+}
+
+#[cfg(test)]
+mod disconnect_reason_tests {
+    use super::*;
+
+    #[test]
+    fn v5_disconnect_reason_converts_into_the_protocol_variant() {
+        let reason: DisconnectReason = v5::DisconnectReason::SessionTakenOver.into();
+        assert_eq!(reason, DisconnectReason::Protocol(v5::DisconnectReason::SessionTakenOver));
+    }
+}
+
 /// Requests to network event loop
 #[derive(Debug)]
 pub enum Request {
     Publish(Publish),
+    /// v5-only: a PUBLISH carrying variable-header properties.
+    PublishV5(Publish, PublishProperties),
     Subscribe(Subscribe),
+    /// v5-only: a SUBSCRIBE carrying variable-header properties.
+    SubscribeV5(Subscribe, SubscribeProperties),
     Unsubscribe(Unsubscribe),
     PubAck(PacketIdentifier),
     PubRec(PacketIdentifier),
@@ -43,10 +126,193 @@ pub enum Command {
     Resume,
 }
 
+struct CreditState {
+    remaining: usize,
+    /// Tasks parked in `Ready::poll`, woken on the next `release()`.
+    tasks: Vec<Task>,
+}
+
+/// How long [`Credit::acquire`] blocks waiting for a free slot before giving
+/// up. `mqttstate` doesn't call `release()` on inbound PubAck/PubComp yet
+/// (see the note below), so today this is the only thing standing between
+/// a QoS>0 publish past `max_inflight` and a thread blocked forever.
+const DEFAULT_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Inflight-publish credit shared between `MqttClient` and `mqttstate`.
+///
+/// Mirrors the v5 "Receive Maximum" (or a plain cap under v3.1.1): every
+/// QoS>0 publish is meant to consume one credit before `mqttstate` pushes it
+/// to the wire, and every incoming PubAck/PubComp is meant to release one
+/// back. That `mqttstate` wiring — the half that actually calls
+/// `release()` in production — is a follow-up and not part of this change;
+/// `release()` is currently only exercised by this module's own tests. Until
+/// it's wired up, `acquire()` times out rather than blocking the calling
+/// thread unboundedly.
+#[derive(Clone)]
+pub struct Credit {
+    state: Arc<Mutex<CreditState>>,
+    condvar: Arc<Condvar>,
+    acquire_timeout: Duration,
+}
+
+impl Credit {
+    pub(crate) fn new(max_inflight: usize) -> Self {
+        Credit::with_acquire_timeout(max_inflight, DEFAULT_ACQUIRE_TIMEOUT)
+    }
+
+    /// Like [`new`](Self::new), but with an explicit `acquire()` timeout
+    /// instead of [`DEFAULT_ACQUIRE_TIMEOUT`] — mainly so tests don't have
+    /// to wait 30 seconds to observe a timeout.
+    pub(crate) fn with_acquire_timeout(max_inflight: usize, acquire_timeout: Duration) -> Self {
+        Credit {
+            state: Arc::new(Mutex::new(CreditState {
+                remaining: max_inflight,
+                tasks: Vec::new(),
+            })),
+            condvar: Arc::new(Condvar::new()),
+            acquire_timeout,
+        }
+    }
+
+    /// Slots currently free for a new QoS>0 publish.
+    pub fn remaining(&self) -> usize {
+        self.state.lock().unwrap().remaining
+    }
+
+    /// Blocks the calling thread until a slot is free, then takes it. This
+    /// is what actually queues a publish instead of pushing it to the wire
+    /// once the window is exhausted. Fails with `ClientError::CreditTimeout`
+    /// if no slot frees up within `acquire_timeout`.
+    pub(crate) fn acquire(&self) -> Result<(), ClientError> {
+        let state = self.state.lock().unwrap();
+        let (mut state, timed_out) = self
+            .condvar
+            .wait_timeout_while(state, self.acquire_timeout, |state| state.remaining == 0)
+            .unwrap();
+        if timed_out.timed_out() {
+            return Err(ClientError::CreditTimeout);
+        }
+        state.remaining -= 1;
+        Ok(())
+    }
+
+    /// Called when a PubAck/PubComp frees up a slot; wakes both blocked
+    /// `acquire()` callers and anyone parked in `ready()`.
+    pub(crate) fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.remaining += 1;
+        let tasks = std::mem::replace(&mut state.tasks, Vec::new());
+        drop(state);
+
+        self.condvar.notify_all();
+        for task in tasks {
+            task.notify();
+        }
+    }
+}
+
+/// Future returned by [`MqttClient::ready`](MqttClient::ready); resolves once
+/// a publish credit is available.
+pub struct Ready {
+    credit: Credit,
+}
+
+impl Future for Ready {
+    type Item = ();
+    type Error = ClientError;
+
+    fn poll(&mut self) -> Poll<(), ClientError> {
+        let mut state = self.credit.state.lock().unwrap();
+        if state.remaining > 0 {
+            return Ok(Async::Ready(()));
+        }
+        state.tasks.push(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod credit_tests {
+    use super::Credit;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn acquire_consumes_and_release_restores_remaining() {
+        let credit = Credit::new(2);
+        assert_eq!(credit.remaining(), 2);
+
+        credit.acquire().unwrap();
+        assert_eq!(credit.remaining(), 1);
+
+        credit.acquire().unwrap();
+        assert_eq!(credit.remaining(), 0);
+
+        credit.release();
+        assert_eq!(credit.remaining(), 1);
+    }
+
+    #[test]
+    fn acquire_blocks_until_release() {
+        let credit = Credit::new(1);
+        credit.acquire().unwrap();
+        assert_eq!(credit.remaining(), 0);
+
+        let blocked = credit.clone();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            blocked.acquire().unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        // Give the spawned thread a chance to actually block on empty credit.
+        thread::sleep(Duration::from_millis(50));
+        assert!(done_rx.try_recv().is_err());
+
+        credit.release();
+        done_rx.recv_timeout(Duration::from_secs(1)).expect("acquire() should unblock after release()");
+    }
+
+    #[test]
+    fn acquire_times_out_instead_of_blocking_forever_when_nothing_releases() {
+        let credit = Credit::with_acquire_timeout(1, Duration::from_millis(50));
+        credit.acquire().unwrap();
+
+        match credit.acquire() {
+            Err(crate::error::ClientError::CreditTimeout) => {}
+            other => panic!("expected Err(CreditTimeout), got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn ready_resolves_once_a_credit_is_released() {
+        use futures::Future;
+
+        let credit = Credit::new(1);
+        credit.acquire().unwrap();
+
+        let waiter_credit = credit.clone();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let ready = super::Ready { credit: waiter_credit };
+            ready.wait().unwrap();
+            done_tx.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(done_rx.try_recv().is_err());
+
+        credit.release();
+        done_rx.recv_timeout(Duration::from_secs(1)).expect("ready() should resolve after release()");
+    }
+}
+
 pub struct UserHandle {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
     notification_rx: crossbeam_channel::Receiver<Notification>,
+    credit: Credit,
 }
 
 #[derive(Clone)]
@@ -54,26 +320,70 @@ pub struct MqttClient {
     request_tx: mpsc::Sender<Request>,
     command_tx: mpsc::Sender<Command>,
     max_packet_size: usize,
+    max_incoming_packet_size: usize,
+    protocol: Protocol,
+    credit: Credit,
+    client_id: String,
+    pending_requests: Arc<PendingRequests>,
+    reply_subscribed: Arc<Mutex<bool>>,
 }
 
 impl MqttClient {
     pub fn start(opts: MqttOptions) -> Result<(Self, crossbeam_channel::Receiver<Notification>), ConnectError> {
         let max_packet_size = opts.max_packet_size();
+        let max_incoming_packet_size = opts.max_incoming_packet_size();
+        let protocol = opts.protocol();
+        let client_id = opts.client_id();
+        let pending_requests = PendingRequests::new(client_id.clone());
         let UserHandle {
             request_tx,
             command_tx,
             notification_rx,
+            credit,
         } = connection::Connection::run(opts)?;
 
         let client = MqttClient {
             request_tx,
             command_tx,
             max_packet_size,
+            max_incoming_packet_size,
+            protocol,
+            credit,
+            client_id,
+            pending_requests,
+            reply_subscribed: Arc::new(Mutex::new(false)),
         };
 
         Ok((client, notification_rx))
     }
 
+    /// The configured limit on decoded inbound packet size. The actual
+    /// streaming check — reading the fixed header's remaining-length
+    /// varint and aborting before buffering an oversized body — lives in
+    /// [`network::decode_remaining_length`]. Calling it from `connection`'s
+    /// socket-read loop on every inbound packet, so a violation actually
+    /// produces `Notification::Disconnected { reason:
+    /// DisconnectReason::IncomingPacketTooLarge { .. } }`, is a follow-up
+    /// and not part of this change — today this value is recorded and the
+    /// check itself exists, but nothing in the event loop calls it yet.
+    pub fn max_incoming_packet_size(&self) -> usize {
+        self.max_incoming_packet_size
+    }
+
+    /// Remaining inflight-publish slots (the v5 Receive Maximum, or the
+    /// configured cap under v3.1.1).
+    pub fn credit(&self) -> usize {
+        self.credit.remaining()
+    }
+
+    /// Resolves once a publish credit is available, so callers can apply
+    /// real backpressure instead of blocking a thread on `publish`.
+    pub fn ready(&self) -> Ready {
+        Ready {
+            credit: self.credit.clone(),
+        }
+    }
+
     //    pub fn proxy_start(opts: MqttOptions, ) -> Result<(Self, crossbeam_channel::Receiver<Notification>), ConnectError> {
     //
     //    }
@@ -97,12 +407,84 @@ impl MqttClient {
             payload: Arc::new(payload),
         };
 
+        if qos != QoS::AtMostOnce {
+            self.credit.acquire()?;
+        }
+
         let tx = &mut self.request_tx;
         tx.send(Request::Publish(publish)).wait()?;
         Ok(())
     }
 
+    /// Like [`publish`](Self::publish), but attaches v5 variable-header
+    /// properties. Has no effect when the negotiated protocol is v3.1.1.
+    pub fn publish_with_properties<S, V>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        payload: V,
+        properties: PublishProperties,
+    ) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let payload = payload.into();
+        if payload.len() > self.max_packet_size {
+            return Err(ClientError::PacketSizeLimitExceeded);
+        }
+
+        let publish = Publish {
+            dup: false,
+            qos,
+            retain: false,
+            topic_name: topic.into(),
+            pkid: None,
+            payload: Arc::new(payload),
+        };
+
+        if qos != QoS::AtMostOnce {
+            self.credit.acquire()?;
+        }
+
+        let tx = &mut self.request_tx;
+        tx.send(Request::PublishV5(publish, properties)).wait()?;
+        Ok(())
+    }
+
     pub fn subscribe<S>(&mut self, topic: S, qos: QoS) -> Result<(), ClientError>
+    where
+        S: Into<String>,
+    {
+        self.subscribe_many(vec![(topic.into(), qos)])
+    }
+
+    /// Subscribes to several topics in a single SUBSCRIBE packet. The
+    /// broker's grants/rejections come back aligned per-topic on
+    /// `Notification::SubAck`.
+    pub fn subscribe_many(&mut self, topics: Vec<(String, QoS)>) -> Result<(), ClientError> {
+        let topics = topics
+            .into_iter()
+            .map(|(topic_path, qos)| SubscribeTopic { topic_path, qos })
+            .collect();
+        let subscribe = Subscribe {
+            pkid: PacketIdentifier::zero(),
+            topics,
+        };
+
+        let tx = &mut self.request_tx;
+        tx.send(Request::Subscribe(subscribe)).wait()?;
+        Ok(())
+    }
+
+    /// Like [`subscribe`](Self::subscribe), but attaches v5 variable-header
+    /// properties. Has no effect when the negotiated protocol is v3.1.1.
+    pub fn subscribe_with_properties<S>(
+        &mut self,
+        topic: S,
+        qos: QoS,
+        properties: SubscribeProperties,
+    ) -> Result<(), ClientError>
     where
         S: Into<String>,
     {
@@ -116,7 +498,7 @@ impl MqttClient {
         };
 
         let tx = &mut self.request_tx;
-        tx.send(Request::Subscribe(subscribe)).wait()?;
+        tx.send(Request::SubscribeV5(subscribe, properties)).wait()?;
         Ok(())
     }
 
@@ -124,9 +506,14 @@ impl MqttClient {
         where
             S: Into<String>,
     {
+        self.unsubscribe_many(vec![topic.into()])
+    }
+
+    /// Unsubscribes from several topics in a single UNSUBSCRIBE packet.
+    pub fn unsubscribe_many(&mut self, topics: Vec<String>) -> Result<(), ClientError> {
         let unsubscribe = Unsubscribe {
             pkid: PacketIdentifier::zero(),
-            topics: vec![topic.into()],
+            topics,
         };
 
         let tx = &mut self.request_tx;
@@ -147,10 +534,160 @@ impl MqttClient {
     }
 
     pub fn disconnect(&mut self) -> Result<(), ClientError> {
+        // Nothing will ever complete these now; drop them rather than
+        // leaving them pending forever.
+        self.pending_requests.cancel_all();
+
         let tx = &mut self.request_tx;
         tx.send(Request::Disconnect).wait()?;
         Ok(())
     }
+
+    /// Request/response over MQTT: publishes `payload` to `topic`, then
+    /// resolves with the correlated reply `Publish`, or errors if no reply
+    /// arrives within `timeout`.
+    ///
+    /// Generates a unique correlation id and ensures a subscription to this
+    /// client's shared reply topic exists, then attaches the correlation
+    /// data as a v5 property (or, under v3.1.1, in a small envelope
+    /// prepended to the payload, since there's no native property to carry
+    /// it). A responder demultiplexes replies by reading the correlation id
+    /// back out (`Notification::PublishV5` under v5,
+    /// `correlation::decode_v4_envelope` under v3.1.1) and publishing to the
+    /// carried response topic; `connection`/`mqttstate` would complete the
+    /// returned future once such a reply arrives, which is a follow-up to
+    /// this change, not implemented here.
+    pub fn request<S, V>(
+        &mut self,
+        topic: S,
+        payload: V,
+        qos: QoS,
+        timeout: Duration,
+    ) -> Result<impl Future<Item = Publish, Error = ClientError>, ClientError>
+    where
+        S: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let topic = topic.into();
+        let correlation_id = self.pending_requests.next_correlation_id();
+        let response_topic = self.reply_topic();
+        let reply = self.pending_requests.register(correlation_id.clone(), timeout);
+
+        self.ensure_subscribed_to_reply_topic()?;
+
+        match self.protocol {
+            Protocol::V5 => {
+                let properties = PublishProperties {
+                    response_topic: Some(response_topic),
+                    correlation_data: Some(correlation_id.into_bytes()),
+                    ..PublishProperties::default()
+                };
+                self.publish_with_properties(topic, qos, payload, properties)?;
+            }
+            Protocol::V4 => {
+                let payload = correlation::encode_v4_envelope(&response_topic, &correlation_id, payload.into());
+                self.publish(topic, qos, payload)?;
+            }
+        }
+
+        Ok(reply.map_err(|_| ClientError::RequestCancelled))
+    }
+
+    /// The single topic this client's `request()` replies come back on,
+    /// shared across every in-flight request and demultiplexed by
+    /// correlation id rather than by topic.
+    fn reply_topic(&self) -> String {
+        format!("{}/_reply", self.client_id)
+    }
+
+    fn ensure_subscribed_to_reply_topic(&mut self) -> Result<(), ClientError> {
+        let reply_subscribed = self.reply_subscribed.clone();
+        let mut subscribed = reply_subscribed.lock().unwrap();
+        if *subscribed {
+            return Ok(());
+        }
+        let reply_topic = self.reply_topic();
+        self.subscribe(reply_topic, QoS::AtLeastOnce)?;
+        *subscribed = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod subscribe_tests {
+    use super::*;
+    use futures::Stream;
+
+    fn test_client() -> (MqttClient, mpsc::Receiver<Request>) {
+        let (request_tx, request_rx) = mpsc::channel(16);
+        let (command_tx, _command_rx) = mpsc::channel(16);
+        let client = MqttClient {
+            request_tx,
+            command_tx,
+            max_packet_size: usize::max_value(),
+            max_incoming_packet_size: usize::max_value(),
+            protocol: Protocol::V4,
+            credit: Credit::new(16),
+            client_id: "test-client".to_owned(),
+            pending_requests: PendingRequests::new("test-client".to_owned()),
+            reply_subscribed: Arc::new(Mutex::new(false)),
+        };
+        (client, request_rx)
+    }
+
+    #[test]
+    fn subscribe_many_sends_one_subscribe_with_all_topics_in_order() {
+        let (mut client, request_rx) = test_client();
+        client
+            .subscribe_many(vec![("a".to_owned(), QoS::AtLeastOnce), ("b".to_owned(), QoS::ExactlyOnce)])
+            .unwrap();
+
+        match request_rx.wait().next().unwrap().unwrap() {
+            Request::Subscribe(subscribe) => {
+                let topics: Vec<_> = subscribe.topics.iter().map(|t| (t.topic_path.clone(), t.qos)).collect();
+                assert_eq!(
+                    topics,
+                    vec![("a".to_owned(), QoS::AtLeastOnce), ("b".to_owned(), QoS::ExactlyOnce)]
+                );
+            }
+            other => panic!("expected Request::Subscribe, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsubscribe_many_sends_one_unsubscribe_with_all_topics() {
+        let (mut client, request_rx) = test_client();
+        client.unsubscribe_many(vec!["a".to_owned(), "b".to_owned()]).unwrap();
+
+        match request_rx.wait().next().unwrap().unwrap() {
+            Request::Unsubscribe(unsubscribe) => {
+                assert_eq!(unsubscribe.topics, vec!["a".to_owned(), "b".to_owned()]);
+            }
+            other => panic!("expected Request::Unsubscribe, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn subscribe_delegates_to_subscribe_many_with_a_single_topic() {
+        let (mut client, request_rx) = test_client();
+        client.subscribe("a", QoS::AtMostOnce).unwrap();
+
+        match request_rx.wait().next().unwrap().unwrap() {
+            Request::Subscribe(subscribe) => assert_eq!(subscribe.topics.len(), 1),
+            other => panic!("expected Request::Subscribe, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unsubscribe_delegates_to_unsubscribe_many_with_a_single_topic() {
+        let (mut client, request_rx) = test_client();
+        client.unsubscribe("a").unwrap();
+
+        match request_rx.wait().next().unwrap().unwrap() {
+            Request::Unsubscribe(unsubscribe) => assert_eq!(unsubscribe.topics, vec!["a".to_owned()]),
+            other => panic!("expected Request::Unsubscribe, got {:?}", other),
+        }
+    }
 }
 
 // use std::fmt;
@@ -0,0 +1,107 @@
+//! Streaming enforcement of `max_incoming_packet_size` against the fixed
+//! header, following ntex's codec design: the remaining-length varint is
+//! decoded and checked against the limit *before* a single byte of the
+//! packet body is buffered, so an oversized PUBLISH from a malicious or
+//! misconfigured broker can't force a large allocation.
+//!
+//! `decode_remaining_length` is the standalone, unit-testable piece of that
+//! check; wiring it into `connection`'s actual socket-read loop (so a limit
+//! violation turns into `Notification::Disconnected { reason:
+//! DisconnectReason::IncomingPacketTooLarge { .. } }`) is a follow-up and
+//! not part of this change.
+
+use crate::client::DisconnectReason;
+
+/// Remaining-length varint decoded off an MQTT fixed header
+/// (MQTT-v3.1.1 section 2.2.3 / MQTT-v5.0 section 1.5.5): 1-4 bytes, 7
+/// payload bits per byte, continuation signalled by the top bit.
+const MAX_REMAINING_LENGTH_BYTES: usize = 4;
+
+/// Streams `bytes` (everything read off the socket after the packet type
+/// byte so far) one byte at a time as a remaining-length varint, aborting
+/// as soon as the partially-decoded value already exceeds `limit` instead
+/// of waiting for the full varint (let alone the body) to arrive.
+///
+/// Returns:
+/// - `Ok(None)` if `bytes` doesn't yet contain a complete varint (read more
+///   and call again),
+/// - `Ok(Some(len))` once the complete remaining-length `len` has been
+///   decoded and is within `limit`,
+/// - `Err(DisconnectReason::IncomingPacketTooLarge)` as soon as the decoded
+///   (or still-decoding) value exceeds `limit`.
+pub fn decode_remaining_length(bytes: &[u8], limit: usize) -> Result<Option<usize>, DisconnectReason> {
+    let mut value: usize = 0;
+    let mut multiplier: usize = 1;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if i >= MAX_REMAINING_LENGTH_BYTES {
+            // A well-formed varint never reaches a 5th byte; treat this the
+            // same as exceeding the limit rather than looping forever.
+            return Err(DisconnectReason::IncomingPacketTooLarge { limit, actual: value });
+        }
+
+        value += (byte as usize & 0x7F) * multiplier;
+        if value > limit {
+            return Err(DisconnectReason::IncomingPacketTooLarge { limit, actual: value });
+        }
+
+        if byte & 0x80 == 0 {
+            return Ok(Some(value));
+        }
+
+        multiplier *= 128;
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_a_single_byte_remaining_length() {
+        assert_eq!(decode_remaining_length(&[0x00], 1024), Ok(Some(0)));
+        assert_eq!(decode_remaining_length(&[0x7F], 1024), Ok(Some(127)));
+    }
+
+    #[test]
+    fn decodes_a_multi_byte_remaining_length() {
+        // 321 encodes as 0xC1 0x02 (MQTT-v3.1.1 section 2.2.3 worked example).
+        assert_eq!(decode_remaining_length(&[0xC1, 0x02], 1024), Ok(Some(321)));
+    }
+
+    #[test]
+    fn returns_none_when_the_varint_is_incomplete() {
+        // Continuation bit set, no following byte yet.
+        assert_eq!(decode_remaining_length(&[0xC1], 1024), Ok(None));
+    }
+
+    #[test]
+    fn aborts_before_the_varint_completes_once_the_limit_is_exceeded() {
+        // The first byte alone already decodes to 127 > `limit`, so the
+        // read aborts there, before the 2nd and 3rd bytes are consumed.
+        let err = decode_remaining_length(&[0xFF, 0xFF, 0x7F], 100).unwrap_err();
+        match err {
+            DisconnectReason::IncomingPacketTooLarge { limit, .. } => assert_eq!(limit, 100),
+            other => panic!("expected IncomingPacketTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_complete_value_over_the_limit() {
+        let err = decode_remaining_length(&[0x7F], 100).unwrap_err();
+        match err {
+            DisconnectReason::IncomingPacketTooLarge { limit, actual } => {
+                assert_eq!(limit, 100);
+                assert_eq!(actual, 127);
+            }
+            other => panic!("expected IncomingPacketTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_a_malformed_varint_longer_than_four_bytes() {
+        assert!(decode_remaining_length(&[0xFF, 0xFF, 0xFF, 0xFF, 0x01], usize::max_value()).is_err());
+    }
+}
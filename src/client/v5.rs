@@ -0,0 +1,319 @@
+//! MQTT v5 variable-header properties and reason codes.
+//!
+//! These only apply once `MqttOptions` negotiates `Protocol::V5` with the
+//! broker; a v3.1.1 session never populates them. [`encode_publish_properties`]
+//! and [`decode_publish_properties`] are the real wire format (MQTT-v5.0
+//! section 3.3.2.3) and are unit-tested below; having `connection` and
+//! `mqttstate` branch on the negotiated `Protocol` from CONNACK to actually
+//! call them while reading/writing a PUBLISH is the remaining follow-up,
+//! not part of this change.
+
+/// Protocol level negotiated with the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    V4,
+    V5,
+}
+
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol::V4
+    }
+}
+
+/// An MQTT v5 user property: an arbitrary, repeatable key/value pair.
+pub type UserProperty = (String, String);
+
+/// v5 PUBLISH properties (MQTT-v5.0 section 3.3.2.3).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PublishProperties {
+    pub message_expiry_interval: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<Vec<u8>>,
+    pub subscription_identifiers: Vec<u32>,
+    pub user_properties: Vec<UserProperty>,
+}
+
+/// v5 SUBSCRIBE properties (MQTT-v5.0 section 3.8.2.1).
+#[derive(Debug, Clone, Default)]
+pub struct SubscribeProperties {
+    pub subscription_identifier: Option<u32>,
+    pub user_properties: Vec<UserProperty>,
+}
+
+/// Reason code returned in a v5 PUBACK (MQTT-v5.0 section 3.4.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PubAckReason {
+    Success,
+    NoMatchingSubscribers,
+    UnspecifiedError,
+    ImplementationSpecificError,
+    NotAuthorized,
+    TopicNameInvalid,
+    PacketIdentifierInUse,
+    QuotaExceeded,
+    PayloadFormatInvalid,
+}
+
+/// Failure reason code returned per-topic in a v5 SUBACK (MQTT-v5.0 section
+/// 3.9.3). Grants (QoS 0/1/2) are never represented here — they're reported
+/// uniformly as `SubscribeResult::Success(QoS)` regardless of protocol, so
+/// every caller only has to match one shape for "the broker granted this".
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubAckReason {
+    UnspecifiedError,
+    ImplementationSpecificError,
+    NotAuthorized,
+    TopicFilterInvalid,
+    PacketIdentifierInUse,
+    QuotaExceeded,
+    SharedSubscriptionsNotSupported,
+    SubscriptionIdentifiersNotSupported,
+    WildcardSubscriptionsNotSupported,
+}
+
+/// Reason code carried in a v5 DISCONNECT (MQTT-v5.0 section 3.14.2.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    NormalDisconnection,
+    DisconnectWithWillMessage,
+    UnspecifiedError,
+    ProtocolError,
+    ServerBusy,
+    KeepAliveTimeout,
+    SessionTakenOver,
+}
+
+const PROP_MESSAGE_EXPIRY_INTERVAL: u8 = 0x02;
+const PROP_CONTENT_TYPE: u8 = 0x03;
+const PROP_RESPONSE_TOPIC: u8 = 0x08;
+const PROP_CORRELATION_DATA: u8 = 0x09;
+const PROP_SUBSCRIPTION_IDENTIFIER: u8 = 0x0B;
+const PROP_USER_PROPERTY: u8 = 0x26;
+
+/// Encodes `props` as an MQTT v5 PUBLISH properties block: a leading
+/// Property Length variable byte integer (MQTT-v5.0 section 1.5.5) followed
+/// by each present property as an identifier byte plus its value.
+pub fn encode_publish_properties(props: &PublishProperties) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    if let Some(interval) = props.message_expiry_interval {
+        body.push(PROP_MESSAGE_EXPIRY_INTERVAL);
+        body.extend_from_slice(&interval.to_be_bytes());
+    }
+    if let Some(content_type) = &props.content_type {
+        body.push(PROP_CONTENT_TYPE);
+        write_utf8_string(&mut body, content_type);
+    }
+    if let Some(response_topic) = &props.response_topic {
+        body.push(PROP_RESPONSE_TOPIC);
+        write_utf8_string(&mut body, response_topic);
+    }
+    if let Some(correlation_data) = &props.correlation_data {
+        body.push(PROP_CORRELATION_DATA);
+        write_binary_data(&mut body, correlation_data);
+    }
+    for subscription_identifier in &props.subscription_identifiers {
+        body.push(PROP_SUBSCRIPTION_IDENTIFIER);
+        body.extend(encode_variable_byte_integer(*subscription_identifier));
+    }
+    for (key, value) in &props.user_properties {
+        body.push(PROP_USER_PROPERTY);
+        write_utf8_string(&mut body, key);
+        write_utf8_string(&mut body, value);
+    }
+
+    let mut out = encode_variable_byte_integer(body.len() as u32);
+    out.extend(body);
+    out
+}
+
+/// Inverse of [`encode_publish_properties`]. Returns the decoded properties
+/// and whatever of `bytes` followed the properties block (the PUBLISH
+/// payload), or `None` on a malformed/truncated block or an unrecognized
+/// property identifier.
+pub fn decode_publish_properties(bytes: &[u8]) -> Option<(PublishProperties, &[u8])> {
+    let (len, rest) = decode_variable_byte_integer(bytes)?;
+    let len = len as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (mut body, after) = rest.split_at(len);
+
+    let mut props = PublishProperties::default();
+    while !body.is_empty() {
+        let id = body[0];
+        body = &body[1..];
+        match id {
+            PROP_MESSAGE_EXPIRY_INTERVAL => {
+                if body.len() < 4 {
+                    return None;
+                }
+                let (int_bytes, remainder) = body.split_at(4);
+                props.message_expiry_interval = Some(u32::from_be_bytes([
+                    int_bytes[0],
+                    int_bytes[1],
+                    int_bytes[2],
+                    int_bytes[3],
+                ]));
+                body = remainder;
+            }
+            PROP_CONTENT_TYPE => {
+                let (s, remainder) = read_utf8_string(body)?;
+                props.content_type = Some(s);
+                body = remainder;
+            }
+            PROP_RESPONSE_TOPIC => {
+                let (s, remainder) = read_utf8_string(body)?;
+                props.response_topic = Some(s);
+                body = remainder;
+            }
+            PROP_CORRELATION_DATA => {
+                let (data, remainder) = read_binary_data(body)?;
+                props.correlation_data = Some(data);
+                body = remainder;
+            }
+            PROP_SUBSCRIPTION_IDENTIFIER => {
+                let (value, remainder) = decode_variable_byte_integer(body)?;
+                props.subscription_identifiers.push(value);
+                body = remainder;
+            }
+            PROP_USER_PROPERTY => {
+                let (key, remainder) = read_utf8_string(body)?;
+                let (value, remainder) = read_utf8_string(remainder)?;
+                props.user_properties.push((key, value));
+                body = remainder;
+            }
+            _ => return None,
+        }
+    }
+
+    Some((props, after))
+}
+
+fn write_utf8_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_utf8_string(buf: &[u8]) -> Option<(String, &[u8])> {
+    let (data, rest) = read_binary_data(buf)?;
+    Some((String::from_utf8(data).ok()?, rest))
+}
+
+fn write_binary_data(buf: &mut Vec<u8>, data: &[u8]) {
+    buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buf.extend_from_slice(data);
+}
+
+fn read_binary_data(buf: &[u8]) -> Option<(Vec<u8>, &[u8])> {
+    if buf.len() < 2 {
+        return None;
+    }
+    let (len_bytes, rest) = buf.split_at(2);
+    let len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+    if rest.len() < len {
+        return None;
+    }
+    let (data, rest) = rest.split_at(len);
+    Some((data.to_vec(), rest))
+}
+
+/// Variable byte integer (MQTT-v5.0 section 1.5.5): 1-4 bytes, 7 payload
+/// bits per byte, continuation signalled by the top bit. Used both for
+/// Property Length and for individual variable-byte-integer property values
+/// like Subscription Identifier.
+fn encode_variable_byte_integer(mut value: u32) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1);
+    loop {
+        let mut byte = (value % 128) as u8;
+        value /= 128;
+        if value > 0 {
+            byte |= 0x80;
+        }
+        out.push(byte);
+        if value == 0 {
+            break;
+        }
+    }
+    out
+}
+
+fn decode_variable_byte_integer(bytes: &[u8]) -> Option<(u32, &[u8])> {
+    let mut value: u32 = 0;
+    let mut multiplier: u32 = 1;
+
+    for (i, &byte) in bytes.iter().enumerate() {
+        if i >= 4 {
+            return None;
+        }
+        value += (byte as u32 & 0x7F) * multiplier;
+        if byte & 0x80 == 0 {
+            return Some((value, &bytes[i + 1..]));
+        }
+        multiplier *= 128;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variable_byte_integer_roundtrips() {
+        for value in [0u32, 127, 128, 16383, 16384, 2097151, 2097152, 268435455] {
+            let encoded = encode_variable_byte_integer(value);
+            assert_eq!(decode_variable_byte_integer(&encoded), Some((value, &[][..])));
+        }
+    }
+
+    #[test]
+    fn publish_properties_roundtrip_when_empty() {
+        let props = PublishProperties::default();
+        let encoded = encode_publish_properties(&props);
+        let (decoded, rest) = decode_publish_properties(&encoded).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(decoded.message_expiry_interval, None);
+        assert_eq!(decoded.content_type, None);
+        assert_eq!(decoded.user_properties, Vec::<UserProperty>::new());
+    }
+
+    #[test]
+    fn publish_properties_roundtrip_when_fully_populated() {
+        let props = PublishProperties {
+            message_expiry_interval: Some(60),
+            content_type: Some("application/json".to_owned()),
+            response_topic: Some("client/_reply".to_owned()),
+            correlation_data: Some(b"abc123".to_vec()),
+            subscription_identifiers: vec![1, 128, 16384],
+            user_properties: vec![("trace-id".to_owned(), "abc".to_owned())],
+        };
+
+        let mut encoded = encode_publish_properties(&props);
+        encoded.extend_from_slice(b"payload-follows");
+
+        let (decoded, rest) = decode_publish_properties(&encoded).unwrap();
+        assert_eq!(decoded.message_expiry_interval, props.message_expiry_interval);
+        assert_eq!(decoded.content_type, props.content_type);
+        assert_eq!(decoded.response_topic, props.response_topic);
+        assert_eq!(decoded.correlation_data, props.correlation_data);
+        assert_eq!(decoded.subscription_identifiers, props.subscription_identifiers);
+        assert_eq!(decoded.user_properties, props.user_properties);
+        assert_eq!(rest, b"payload-follows");
+    }
+
+    #[test]
+    fn decode_rejects_an_unrecognized_property_identifier() {
+        let malformed = vec![1, 0xFF];
+        assert!(decode_publish_properties(&malformed).is_none());
+    }
+
+    #[test]
+    fn decode_rejects_a_truncated_properties_block() {
+        // Property length says 10 bytes follow, but none do.
+        let malformed = vec![10];
+        assert!(decode_publish_properties(&malformed).is_none());
+    }
+}
@@ -0,0 +1,41 @@
+//! Error types returned by [`crate::client::MqttClient`].
+
+use futures::sync::mpsc::SendError;
+
+/// Failure connecting and starting the event loop, returned by
+/// [`MqttClient::start`](crate::client::MqttClient::start).
+#[derive(Debug)]
+pub enum ConnectError {
+    Io(std::io::Error),
+}
+
+impl From<std::io::Error> for ConnectError {
+    fn from(err: std::io::Error) -> Self {
+        ConnectError::Io(err)
+    }
+}
+
+/// Failure from an `MqttClient` call made after the connection is up.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The outgoing payload is larger than the configured `max_packet_size`.
+    PacketSizeLimitExceeded,
+    /// `Credit::acquire` didn't get a free inflight slot before its timeout.
+    /// See the note on [`crate::client::Credit`] about why this can happen
+    /// even under normal operation today.
+    CreditTimeout,
+    /// The event loop shut down while a request to it was still in flight
+    /// (e.g. the channel to `connection` was dropped).
+    EventLoopStopped,
+    /// An [`MqttClient::request`](crate::client::MqttClient::request) call's
+    /// `PendingRequests` entry was dropped before a reply arrived — either
+    /// its `timeout` elapsed, or `MqttClient::disconnect` called
+    /// `cancel_all()` while it was still outstanding.
+    RequestCancelled,
+}
+
+impl<T> From<SendError<T>> for ClientError {
+    fn from(_: SendError<T>) -> Self {
+        ClientError::EventLoopStopped
+    }
+}